@@ -0,0 +1,10 @@
+#[macro_use]
+extern crate bitflags;
+extern crate byteorder;
+extern crate libc;
+#[macro_use]
+extern crate lazy_static;
+
+pub mod proc_structures;
+pub mod proc_io;
+pub mod proc_utils;
@@ -3,12 +3,10 @@ use std::io::prelude::*;
 use std::io;
 use std::fmt;
 
-use std::collections::HashMap;
 use byteorder::{NativeEndian, ReadBytesExt};
 
-/// Page size for many linux variants (at least Ubuntu..)
-/// Find out hot to look it up programatically in Rust (e.g. `getpagesize` in glibc).
-pub const LINUX_PAGE_SIZE: usize = 4096;
+// reuse the live tree's sysconf(_SC_PAGESIZE)-backed page_size() rather than duplicating it here.
+use proc_utils::process::memory::page_size;
 
 /// Enum to express the process states from `/proc/[pid]/stat`
 /// The comment next to the variant is the shortcut in the stat file.
@@ -73,6 +71,117 @@ impl MemoryPermissions {
 #[derive(Debug)]
 pub struct MemoryRange(usize, usize);
 
+/// RSS/PSS/Swap accounting for a single region, as reported by `/proc/[pid]/smaps`.
+///
+/// All fields are in kB, matching the units used in the smaps file itself.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SmapsStats {
+    pub size_kb: usize,
+    pub rss_kb: usize,
+    pub pss_kb: usize,
+    pub shared_clean_kb: usize,
+    pub shared_dirty_kb: usize,
+    pub private_clean_kb: usize,
+    pub private_dirty_kb: usize,
+    pub swap_kb: usize,
+    pub swap_pss_kb: usize,
+}
+
+impl SmapsStats {
+    /// Parse the `Key:   value kB` lines that follow a smaps stanza header.
+    fn new_from_lines<'a, I: Iterator<Item=&'a str>>(lines: I) -> Self {
+        let mut stats = SmapsStats::default();
+
+        for line in lines {
+            let mut fields = line.split_whitespace();
+            let key = match fields.next() {
+                Some(key) => key,
+                None => continue,
+            };
+            let value = match fields.next().and_then(|v| v.parse::<usize>().ok()) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            match key {
+                "Size:" => stats.size_kb = value,
+                "Rss:" => stats.rss_kb = value,
+                "Pss:" => stats.pss_kb = value,
+                "Shared_Clean:" => stats.shared_clean_kb = value,
+                "Shared_Dirty:" => stats.shared_dirty_kb = value,
+                "Private_Clean:" => stats.private_clean_kb = value,
+                "Private_Dirty:" => stats.private_dirty_kb = value,
+                "Swap:" => stats.swap_kb = value,
+                "SwapPss:" => stats.swap_pss_kb = value,
+                _ => {},
+            }
+        }
+
+        stats
+    }
+
+    /// Sum several stats together, e.g. to roll a process' regions up into a total.
+    fn add(&mut self, other: &SmapsStats) {
+        self.size_kb += other.size_kb;
+        self.rss_kb += other.rss_kb;
+        self.pss_kb += other.pss_kb;
+        self.shared_clean_kb += other.shared_clean_kb;
+        self.shared_dirty_kb += other.shared_dirty_kb;
+        self.private_clean_kb += other.private_clean_kb;
+        self.private_dirty_kb += other.private_dirty_kb;
+        self.swap_kb += other.swap_kb;
+        self.swap_pss_kb += other.swap_pss_kb;
+    }
+}
+
+/// The decoded contents of a single 64-bit `/proc/[pid]/pagemap` entry.
+///
+/// Unlike a simple present/absent check, this keeps the swap location and the flag bits the
+/// kernel sets on present pages, so callers can tell resident, swapped-out and never-faulted
+/// pages apart instead of having the latter two silently dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageInfo {
+    /// The page is resident in RAM at page frame number `pfn` (bit 63).
+    Present {
+        pfn: usize,
+        soft_dirty: bool,
+        exclusive: bool,
+        file_mapped: bool,
+    },
+    /// The page has been swapped out to `swap_type`/`swap_offset` (bit 62).
+    Swapped {
+        swap_type: usize,
+        swap_offset: usize,
+        soft_dirty: bool,
+    },
+    /// The page has never been faulted in, or we otherwise have no information about it.
+    Absent,
+}
+
+impl PageInfo {
+    /// Decode a raw pagemap entry according to `Documentation/admin-guide/mm/pagemap.rst`.
+    pub fn new(page_entry: u64) -> Self {
+        let present = page_entry & (1 << 63) != 0;
+        let swapped = page_entry & (1 << 62) != 0;
+        let file_mapped = page_entry & (1 << 61) != 0;
+        let exclusive = page_entry & (1 << 57) != 0;
+        let soft_dirty = page_entry & (1 << 55) != 0;
+
+        if present {
+            // bits 0-54 hold the page frame number
+            let pfn = (page_entry & ((1 << 55) - 1)) as usize;
+            PageInfo::Present { pfn, soft_dirty, exclusive, file_mapped }
+        } else if swapped {
+            // bits 0-4 hold the swap type, bits 5-54 the swap offset
+            let swap_type = (page_entry & 0b0001_1111) as usize;
+            let swap_offset = ((page_entry >> 5) & ((1 << 50) - 1)) as usize;
+            PageInfo::Swapped { swap_type, swap_offset, soft_dirty }
+        } else {
+            PageInfo::Absent
+        }
+    }
+}
+
 /// Describes a memory region as contained in `/proc/[pid]/maps`
 #[derive(Debug)]
 pub struct MemoryRegion {
@@ -84,8 +193,11 @@ pub struct MemoryRegion {
     offset: usize,
     pathname: Option<String>,
 
-    // the corresponding physical regions that make up the virtual range
-    physical_regions: Option<HashMap<usize, MemoryRange>>,
+    // one `PageInfo` per virtual page in the region, in address order
+    physical_pages: Option<Vec<PageInfo>>,
+
+    // RSS/PSS/Swap accounting for this region, if `/proc/[pid]/smaps` was read
+    smaps: Option<SmapsStats>,
 }
 
 impl MemoryRegion {
@@ -94,11 +206,9 @@ impl MemoryRegion {
             println!("Finding physical maps for {}", self.pathname.as_ref().unwrap());
         }
 
-        let mut physical_map: HashMap<usize, MemoryRange> = HashMap::new();
-
         // start and end page numbers
-        let page_start = (self.virtual_pages.0 / LINUX_PAGE_SIZE);
-        let page_end = (self.virtual_pages.1 / LINUX_PAGE_SIZE);
+        let page_start = self.virtual_pages.0 / page_size();
+        let page_end = self.virtual_pages.1 / page_size();
 
         // page start and read length in bytes (one page has a 64bit entry)
         let page_start_bytes = page_start * 8;
@@ -109,70 +219,21 @@ impl MemoryRegion {
 
         // read the all page indices at once
         let mut byte_buf = vec![0u8; read_length_bytes];
-        pagemap.read(&mut byte_buf)?;
+        pagemap.read_exact(&mut byte_buf)?;
 
         // convert bytes to u64
         let mut buf_rdr = io::Cursor::new(byte_buf);
         let mut u64_buf = vec![0u64; read_length_bytes / 8];
         buf_rdr.read_u64_into::<NativeEndian>(&mut u64_buf).unwrap();
 
-//        println!("{} -- {}", self.virtual_pages.0, self.virtual_pages.1);
-//        println!("{} -- {}", page_start, page_end);
-//        for (a,b) in u64_buf.iter().zip(page_start..page_end) {
-//            let in_ram = (a & (1 << 63)) != 0;
-//            let page_frame_number = (a & ((1 << 55)-1)) as usize;
-//            println!("{} : {} - {}", in_ram, page_frame_number, b);
-//        }
-//
-//        return Ok(());
-
-        // associate physical pages with their virtual addresses
-        // and filter physical pages which are not in RAM
-        // and map pages in RAM to their physical addresses
-        let ram_pages = u64_buf
-            .iter()
-            .zip(page_start..page_end)
-            .filter(|(page_val, _)| {
-                // the last bit is set if page is in RAM
-                (*page_val & (1 << 63)) != 0
-            })
-            .map(|(page_val, v_page)| {
-                // only keep the bottom 55 bits
-                ((*page_val & ((1 << 55)-1)) as usize, v_page)
-            });
-
-        // iterate over the values and find consecutive mappings to store in our map
-        let mut physical_address: Option<MemoryRange> = None;
-        let mut v_start = 0;
-        let mut last_page_frame_number = 0;
-        for (page_frame_number, v_page) in ram_pages {
-            // start new address range if none exists yet
-            if physical_address.is_none() {
-                physical_address = Some(MemoryRange(page_frame_number, page_frame_number));
-                v_start = v_page;
-            } else {
-                // extend existing range or start new one
-                if page_frame_number == last_page_frame_number+1 {
-                    let phy_adr = physical_address.as_mut().unwrap();
-                    phy_adr.1 = page_frame_number;
-                    assert!(phy_adr.0 < phy_adr.1);
-                } else {
-                    physical_map.insert(v_start, physical_address.unwrap());
-                    physical_address = Some(MemoryRange(page_frame_number, page_frame_number));
-                    v_start = v_page;
-                }
-            }
+        // decode every page entry, so resident, swapped and never-faulted pages are all kept.
+        // `read_length_bytes` reads one word past the end of the region (see above), so drop it.
+        let physical_pages = u64_buf.iter()
+            .take(page_end - page_start)
+            .map(|page_entry| PageInfo::new(*page_entry))
+            .collect::<Vec<_>>();
 
-            last_page_frame_number = page_frame_number;
-        }
-
-        // insert the last physical memory region, if any was found
-        if let Some(physical_mem_range) = physical_address {
-            physical_map.insert(v_start, physical_mem_range);
-        }
-
-        // if the physical address map is empty, insert None. Else insert the map.
-        self.physical_regions = if physical_map.is_empty() { None } else { Some(physical_map) };
+        self.physical_pages = Some(physical_pages);
 
         Ok(())
     }
@@ -208,9 +269,19 @@ impl MemoryRegion {
             offset,
             pathname,
             permissions,
-            physical_regions: None,
+            physical_pages: None,
+            smaps: None,
         }
     }
+
+    /// Check whether this region's virtual range matches a smaps stanza's `start-end` header.
+    fn matches_address_range(&self, start: usize, end: usize) -> bool {
+        self.virtual_pages.0 == start && self.virtual_pages.1 == end
+    }
+
+    pub fn physical_pages(&self) -> Option<&[PageInfo]> {
+        self.physical_pages.as_ref().map(Vec::as_slice)
+    }
 }
 
 
@@ -283,15 +354,122 @@ impl ProcessMemoryMap {
 
         ProcessMemoryMap { regions: mem }
     }
+
+    /// Enrich the already-parsed regions with RSS/PSS/Swap accounting from `/proc/[pid]/smaps`.
+    ///
+    /// Each stanza in the smaps file repeats the `/proc/[pid]/maps` line for a region, followed
+    /// by a block of `Key:   value kB` lines. We match each stanza back to its region by the
+    /// `start-end` address header and parse the key/value block into a `SmapsStats`.
+    pub fn fill_smaps(&mut self, pid: usize) -> io::Result<()> {
+        let smaps_path = format!("/proc/{}/smaps", pid);
+        let mut smaps_file = File::open(&smaps_path)?;
+
+        let mut smaps_text = String::new();
+        smaps_file.read_to_string(&mut smaps_text)?;
+
+        let mut lines = smaps_text.lines().peekable();
+        while let Some(header) = lines.next() {
+            let header_fields = header.split_whitespace().collect::<Vec<_>>();
+            let address = header_fields[0].split('-').collect::<Vec<_>>();
+            let start = usize::from_str_radix(address[0], 16).unwrap();
+            let end = usize::from_str_radix(address[1], 16).unwrap();
+
+            // collect the key/value lines until the next stanza header (or end of file)
+            let mut stat_lines = Vec::new();
+            while let Some(next_line) = lines.peek() {
+                if next_line.splitn(2, '-').next().map_or(false, |s| usize::from_str_radix(s, 16).is_ok()) {
+                    break;
+                }
+                stat_lines.push(lines.next().unwrap());
+            }
+
+            if let Some(region) = self.regions.iter_mut()
+                .find(|region| region.matches_address_range(start, end)) {
+                region.smaps = Some(SmapsStats::new_from_lines(stat_lines.into_iter()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sum the per-region smaps stats into a process-wide total, the way `smaps_rollup` does.
+    pub fn smaps_summary(&self) -> SmapsStats {
+        let mut summary = SmapsStats::default();
+        for region in &self.regions {
+            if let Some(ref smaps) = region.smaps {
+                summary.add(smaps);
+            }
+        }
+
+        summary
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
 }
 
 
+/// Selected `/proc/[pid]/stat` fields beyond `pid`/`comm`/`state`.
+///
+/// Field names and types follow `man 5 proc`; the comm-field join in `get_process_metadata`
+/// already normalizes the field count to 52, so these line up positionally with the documented
+/// stat layout starting at field 4 (`ppid`).
+#[derive(Debug)]
+pub struct Stat {
+    pub ppid: i32,
+    pub pgrp: i32,
+    pub session: i32,
+    pub tty_nr: i32,
+    pub utime: u64,
+    pub stime: u64,
+    pub cutime: i64,
+    pub cstime: i64,
+    pub priority: i64,
+    pub nice: i64,
+    pub num_threads: i64,
+    pub starttime: u64,
+    pub vsize: u64,
+    pub rss: i64,
+    pub rsslim: u64,
+    pub processor: i32,
+    pub rt_priority: u32,
+    pub policy: u32,
+}
+
+impl Stat {
+    /// Construct a `Stat` from the 52 whitespace-split and comm-joined `/proc/[pid]/stat` fields.
+    pub fn new_from_stat_fields(stat_fields: &Vec<String>) -> Self {
+        Stat {
+            ppid: stat_fields[3].parse().unwrap(),
+            pgrp: stat_fields[4].parse().unwrap(),
+            session: stat_fields[5].parse().unwrap(),
+            tty_nr: stat_fields[6].parse().unwrap(),
+            utime: stat_fields[13].parse().unwrap(),
+            stime: stat_fields[14].parse().unwrap(),
+            cutime: stat_fields[15].parse().unwrap(),
+            cstime: stat_fields[16].parse().unwrap(),
+            priority: stat_fields[17].parse().unwrap(),
+            nice: stat_fields[18].parse().unwrap(),
+            num_threads: stat_fields[19].parse().unwrap(),
+            starttime: stat_fields[21].parse().unwrap(),
+            vsize: stat_fields[22].parse().unwrap(),
+            rss: stat_fields[23].parse().unwrap(),
+            rsslim: stat_fields[24].parse().unwrap(),
+            processor: stat_fields[38].parse().unwrap(),
+            rt_priority: stat_fields[39].parse().unwrap(),
+            policy: stat_fields[40].parse().unwrap(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ProcessInformation {
     // Process metadata
     pid: usize,
     comm: String,
     state: ProcessState,
+    stat: Stat,
 
     // The mapped memory of the process.
     memory: ProcessMemoryMap,
@@ -306,12 +484,25 @@ impl ProcessInformation {
             pid,
             comm: stat_fields[1].clone(),
             state: ProcessState::new_from_code(stat_fields[2].chars().next().unwrap()),
+            stat: Stat::new_from_stat_fields(stat_fields),
 
             memory: ProcessMemoryMap::read_virtual_map(pid, true),
         }
     }
 
+    pub fn stat(&self) -> &Stat {
+        &self.stat
+    }
+
     pub fn has_physical_map(&self) -> bool {
         self.memory.regions.last().is_some()
     }
+
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub fn memory(&self) -> &ProcessMemoryMap {
+        &self.memory
+    }
 }
@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use super::process::ProcessInformation;
+
+/// A write-access heatmap for a process, built from repeated soft-dirty reset sampling
+/// (the same technique the kernel's DAMON subsystem uses to estimate working sets).
+///
+/// Each round clears the soft-dirty bit on every page, waits `interval`, then records which
+/// virtual addresses were dirtied since the last clear. Addresses dirtied in more rounds are
+/// "hotter".
+pub struct WriteHeatmap {
+    hit_counts: HashMap<usize, usize>,
+    samples_taken: usize,
+}
+
+impl WriteHeatmap {
+    /// Sample `process`'s write activity over `samples` rounds, `interval` apart.
+    pub fn sample(process: &mut ProcessInformation, interval: Duration, samples: usize) -> io::Result<Self> {
+        let mut hit_counts: HashMap<usize, usize> = HashMap::new();
+
+        for _ in 0..samples {
+            let working_set = process.sample_working_set(interval)?;
+            for region in working_set {
+                for &address in region.dirtied_addresses() {
+                    *hit_counts.entry(address).or_insert(0) += 1;
+                }
+            }
+        }
+
+        Ok(WriteHeatmap { hit_counts, samples_taken: samples })
+    }
+
+    /// How many of the sampled rounds saw `address` dirtied.
+    pub fn hit_count(&self, address: usize) -> usize {
+        *self.hit_counts.get(&address).unwrap_or(&0)
+    }
+
+    /// A normalized 0-1 hotness value: the fraction of rounds in which `address` was dirtied.
+    pub fn hotness(&self, address: usize) -> f64 {
+        if self.samples_taken == 0 {
+            return 0.0;
+        }
+
+        self.hit_count(address) as f64 / self.samples_taken as f64
+    }
+
+    /// The virtual addresses that were dirtied in at least one round.
+    pub fn addresses(&self) -> impl Iterator<Item=&usize> {
+        self.hit_counts.keys()
+    }
+}
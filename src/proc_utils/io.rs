@@ -8,6 +8,7 @@ use std::time::Duration;
 use std::sync::mpsc::{sync_channel, Receiver, TrySendError, TryRecvError};
 
 use super::process::ProcessInformation;
+use super::process::memory::WorkingSetSample;
 
 /// Parse process metadata from the `/proc/[pid]/stat` file
 ///
@@ -103,6 +104,11 @@ pub struct ProcScanner {
 
 impl ProcScanner {
     pub fn new() -> Self {
+        Self::with_cadence(Duration::from_secs(2))
+    }
+
+    /// Like `new`, but scans `/proc` on the given cadence instead of the default 2 seconds.
+    pub fn with_cadence(cadence: Duration) -> Self {
         let (sync_sender, receiver) = sync_channel(1);
 
         // spawn the thread
@@ -110,27 +116,14 @@ impl ProcScanner {
             loop {
                 let process_list = get_process_info();
 
-                // if we have an error while sending, check the error type
-                if let Err(send_error) = sync_sender.try_send(process_list) {
-
-                    // if the queue is full, do nothing.
-                    // at receiver disconnect, shut down thread.
-                    match send_error {
-                        TrySendError::Full(_) => {
-                            println!("Queue is full!")
-                        },
-                        TrySendError::Disconnected(_) => {
-                            println!("Terminating the process scanning thread.");
-                            break;
-                        }
-                    };
-                } else {
-                    println!("Successfully enqueued new process info.");
+                // if the queue is full, drop this scan and try again next cadence tick.
+                // at receiver disconnect, shut down the thread.
+                if let Err(TrySendError::Disconnected(_)) = sync_sender.try_send(process_list) {
+                    break;
                 }
 
-                // only scan the processes every 2 seconds
                 // TODO: think about whether sleeping here is best (or after starting the scan?)
-                thread::sleep(Duration::from_secs(2));
+                thread::sleep(cadence);
             }
         });
 
@@ -144,4 +137,49 @@ impl ProcScanner {
         // TODO: sender disconnect could happen when the process scan thread crashes, handle that
         self.proc_receiver.try_recv().ok()
     }
+}
+
+
+/// Periodically samples a single process's write working set via
+/// `ProcessInformation::sample_working_set`, so a caller can watch how its dirtied pages evolve
+/// over time.
+pub struct WorkingSetScanner {
+    sample_receiver: Receiver<Vec<WorkingSetSample>>,
+}
+
+impl WorkingSetScanner {
+    /// Sample `pid` every `cadence`. Each round clears the soft-dirty bits, waits
+    /// `sample_interval`, then re-reads the pagemap (see `sample_working_set`).
+    pub fn new(pid: usize, sample_interval: Duration, cadence: Duration) -> io::Result<Self> {
+        let mut process = get_pid_info(pid as u64)?;
+        let (sync_sender, receiver) = sync_channel(1);
+
+        // spawn the thread
+        thread::spawn(move || {
+            loop {
+                let samples = match process.sample_working_set(sample_interval) {
+                    Ok(samples) => samples,
+                    // the process likely exited mid-sample; stop rather than sample garbage.
+                    Err(_) => break,
+                };
+
+                // if the queue is full, drop this sample and try again next cadence tick.
+                // at receiver disconnect, shut down the thread.
+                if let Err(TrySendError::Disconnected(_)) = sync_sender.try_send(samples) {
+                    break;
+                }
+
+                thread::sleep(cadence);
+            }
+        });
+
+        Ok(WorkingSetScanner {
+            sample_receiver: receiver,
+        })
+    }
+
+    pub fn working_set(&self) -> Option<Vec<WorkingSetSample>> {
+        // return the receiver's working set samples, if any were returned
+        self.sample_receiver.try_recv().ok()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,4 @@
+pub mod io;
+pub mod process;
+pub mod sharing;
+pub mod heatmap;
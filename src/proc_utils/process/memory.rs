@@ -1,16 +1,25 @@
 use std::fs::File;
 use std::io::prelude::*;
 use std::io;
+use std::thread;
+use std::time::Duration;
 
 use std::fmt;
 
 use std::collections::HashMap;
 use byteorder::{NativeEndian, ReadBytesExt};
 
-/// Page size for many linux variants (at least Ubuntu..)
-///
-/// Could be changed to get it programatically in Rust (e.g. `getpagesize` in glibc).
-pub const LINUX_PAGE_SIZE: usize = 4096;
+lazy_static! {
+    /// The runtime base page size, queried once via `sysconf(_SC_PAGESIZE)` instead of
+    /// assuming 4 KiB. Some systems (e.g. arm64 kernels configured for 16K/64K pages) use a
+    /// different base page size, which would otherwise make every pagemap seek offset wrong.
+    static ref PAGE_SIZE: usize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+}
+
+/// The runtime base page size. See `PAGE_SIZE`.
+pub fn page_size() -> usize {
+    *PAGE_SIZE
+}
 
 
 bitflags! {
@@ -40,6 +49,39 @@ enum PageLocation {
 }
 
 
+bitflags! {
+    /// Flags describing a physical page frame, decoded from `/proc/kpageflags`.
+    ///
+    /// Reading `/proc/kpageflags` requires root/CAP_SYS_ADMIN, so a `PageFrame` simply has no
+    /// flags (`None`) when the file couldn't be opened, rather than failing the whole scan.
+    pub struct PhysicalPageFlags: u32 {
+        const LOCKED = 1 << 0;
+        const ERROR = 1 << 1;
+        const REFERENCED = 1 << 2;
+        const UPTODATE = 1 << 3;
+        const DIRTY = 1 << 4;
+        const LRU = 1 << 5;
+        const ACTIVE = 1 << 6;
+        const SLAB = 1 << 7;
+        const WRITEBACK = 1 << 8;
+        const RECLAIM = 1 << 9;
+        const BUDDY = 1 << 10;
+        const MMAP = 1 << 11;
+        const ANON = 1 << 12;
+        const SWAPCACHE = 1 << 13;
+        const SWAPBACKED = 1 << 14;
+        const COMPOUND_HEAD = 1 << 15;
+        const COMPOUND_TAIL = 1 << 16;
+        const HUGE = 1 << 17;
+        const UNEVICTABLE = 1 << 18;
+        const HWPOISON = 1 << 19;
+        const NOPAGE = 1 << 20;
+        const KSM = 1 << 21;
+        const THP = 1 << 22;
+    }
+}
+
+
 /// Store information about physical page frames.
 ///
 /// By default, only high-level information is present, such as:
@@ -48,6 +90,7 @@ enum PageLocation {
 /// - if the page table entry is soft-dirty
 ///   (this seems to be used mostly for tracing page accesses)
 /// - the page frame number (PFN), if present
+/// - the physical page flags from `/proc/kpageflags`, if present (resident pages only, root only)
 ///
 /// The swap type and offset are currently not stored, if the page is swapped out.
 #[derive(Debug, PartialEq)]
@@ -55,18 +98,55 @@ pub struct PageFrame {
     page_location: PageLocation,
     is_file_page: bool,
     is_soft_dirty: bool,
+    physical_flags: Option<PhysicalPageFlags>,
 }
 
 
 /// A `PageFrameRegion` indicates a number of successive repeating `PageFrame` structs.
+///
+/// `page_order` is non-zero when the run was coalesced from a compound (huge) page: a
+/// `COMPOUND_HEAD` frame followed by its `COMPOUND_TAIL` frames. The region then spans
+/// `2^page_order` base pages, backed by a single huge frame, rather than that many
+/// independently-allocated base pages.
 #[derive(Debug)]
-struct PageFrameRegion {
+pub struct PageFrameRegion {
     frame: PageFrame,
     len: usize,
+    page_order: usize,
 }
 
+impl PageFrameRegion {
+    pub fn frame(&self) -> &PageFrame {
+        &self.frame
+    }
 
-struct PageFrameMap(HashMap<usize, PageFrameRegion>);
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The huge-page order this region was coalesced at: 0 for ordinary base pages, otherwise
+    /// `n` such that the region is backed by `2^n` base pages per frame (e.g. 9 for a 2 MiB
+    /// THP on a 4 KiB base page size).
+    pub fn page_order(&self) -> usize {
+        self.page_order
+    }
+
+    /// The effective size in bytes of a single frame in this region (`page_size()` for base
+    /// pages, or the huge page size if `page_order` is non-zero).
+    pub fn effective_page_size(&self) -> usize {
+        page_size() << self.page_order
+    }
+}
+
+
+pub struct PageFrameMap(HashMap<usize, PageFrameRegion>);
+
+impl PageFrameMap {
+    /// Iterate over the `(virtual start page index, PageFrameRegion)` pairs that make up the map.
+    pub fn regions(&self) -> impl Iterator<Item=(&usize, &PageFrameRegion)> {
+        self.0.iter()
+    }
+}
 
 
 /// Describes a memory region as contained in `/proc/[pid]/maps`
@@ -124,21 +204,37 @@ impl PageFrame {
             };
 
         let is_file_page = page_index & (1 << 61) != 0;
-        let is_soft_dirty = page_index & (55 << 1) != 0;
+        let is_soft_dirty = page_index & (1 << 55) != 0;
 
         PageFrame {
             page_location,
             is_file_page,
             is_soft_dirty,
+            physical_flags: None,
+        }
+    }
+
+    /// The physical page frame number this page is resident at, if it's in RAM.
+    pub fn pfn(&self) -> Option<usize> {
+        match self.page_location {
+            PageLocation::RAM(pfn) => Some(pfn),
+            _ => None,
         }
     }
 
+    /// The physical page flags from `/proc/kpageflags`, if they were read (resident pages only,
+    /// root only).
+    pub fn physical_flags(&self) -> Option<PhysicalPageFlags> {
+        self.physical_flags
+    }
+
     /// Determine if this `PageFrame` comes before the `other: PageFrame`
     /// This function is used to detect runs of identical page frames.
     pub fn is_previous_page(&self, other: &Self) -> bool {
         // the basic attributes have to be equal anyway
         if (self.is_file_page != other.is_file_page) ||
-            (self.is_soft_dirty != other.is_soft_dirty) {
+            (self.is_soft_dirty != other.is_soft_dirty) ||
+            (self.physical_flags != other.physical_flags) {
             return false;
         }
 
@@ -154,6 +250,113 @@ impl PageFrame {
 }
 
 
+/// Read and decode the `/proc/kpageflags` word for `pfn`, or `None` if the file is unavailable
+/// or the read fails (e.g. the frame was freed in the meantime).
+fn read_physical_flags(kpageflags_file: &mut Option<File>, pfn: usize) -> Option<PhysicalPageFlags> {
+    let file = kpageflags_file.as_mut()?;
+    file.seek(io::SeekFrom::Start((pfn * 8) as u64)).ok()?;
+
+    let mut buf = [0u8; 8];
+    file.read_exact(&mut buf).ok()?;
+
+    Some(PhysicalPageFlags::from_bits_truncate(u64::from_ne_bytes(buf) as u32))
+}
+
+
+/// Read the idle-bitmap word covering `pfn` out of `/sys/kernel/mm/page_idle/bitmap`, which
+/// packs one idle bit per PFN: bit `pfn % 64` of the `u64` at byte offset `(pfn / 64) * 8`.
+fn read_idle_word(idle_bitmap: &mut File, pfn: usize) -> io::Result<u64> {
+    idle_bitmap.seek(io::SeekFrom::Start(((pfn / 64) * 8) as u64))?;
+
+    let mut buf = [0u8; 8];
+    idle_bitmap.read_exact(&mut buf)?;
+
+    Ok(u64::from_ne_bytes(buf))
+}
+
+/// Mark `pfn` idle by OR-ing its bit into the word and writing it back.
+fn set_idle_bit(idle_bitmap: &mut File, pfn: usize) -> io::Result<()> {
+    let word = read_idle_word(idle_bitmap, pfn)? | (1 << (pfn % 64));
+    idle_bitmap.seek(io::SeekFrom::Start(((pfn / 64) * 8) as u64))?;
+    idle_bitmap.write_all(&word.to_ne_bytes())
+}
+
+/// Whether `pfn`'s idle bit is still set, i.e. the page has *not* been accessed since it was
+/// last marked idle.
+fn is_idle(idle_bitmap: &mut File, pfn: usize) -> io::Result<bool> {
+    Ok(read_idle_word(idle_bitmap, pfn)? & (1 << (pfn % 64)) != 0)
+}
+
+/// Re-resolve the resident, trackable PFNs for the `page_count` pages starting at page index
+/// `v_page_start`. Pages can be reclaimed or migrated between idle-scan rounds, so callers must
+/// not reuse PFNs from an earlier round. Pages flagged `UNEVICTABLE` or `SLAB` aren't tracked by
+/// the idle bitmap and are excluded.
+fn resolve_trackable_pfns(pagemap: &mut File, kpageflags: &mut Option<File>,
+                           v_page_start: usize, page_count: usize) -> io::Result<Vec<usize>> {
+    pagemap.seek(io::SeekFrom::Start((v_page_start * 8) as u64))?;
+
+    let mut byte_buf = vec![0u8; page_count * 8];
+    pagemap.read_exact(&mut byte_buf)?;
+
+    let mut buf_rdr = io::Cursor::new(byte_buf);
+    let mut u64_buf = vec![0u64; page_count];
+    buf_rdr.read_u64_into::<NativeEndian>(&mut u64_buf).unwrap();
+
+    let pfns = u64_buf.into_iter()
+        .filter_map(|entry| PageFrame::new(entry).pfn())
+        .filter(|&pfn| {
+            let flags = read_physical_flags(kpageflags, pfn).unwrap_or_else(PhysicalPageFlags::empty);
+            !flags.intersects(PhysicalPageFlags::UNEVICTABLE | PhysicalPageFlags::SLAB)
+        })
+        .collect();
+
+    Ok(pfns)
+}
+
+
+/// The compound page order that holds `pages` base pages, i.e. `n` such that `2^n >= pages`.
+fn compound_order(pages: usize) -> usize {
+    if pages <= 1 {
+        return 0;
+    }
+
+    (usize::BITS - (pages - 1).leading_zeros()) as usize
+}
+
+/// Merge `COMPOUND_HEAD` runs with the `COMPOUND_TAIL` run that immediately follows them into a
+/// single `PageFrameRegion`, recording the resulting huge-page order.
+///
+/// The base run-length coalescing in `fill_physical_maps` only merges frames with identical flags, so a
+/// transparent/huge page currently shows up as a lone `COMPOUND_HEAD` frame followed by a run of
+/// `COMPOUND_TAIL` frames. This pass folds that pair into one region so callers see the true
+/// huge-page size instead of a sea of base-page-sized runs.
+fn coalesce_compound_pages(physical_map: &mut PageFrameMap) {
+    let head_starts: Vec<usize> = physical_map.0.iter()
+        .filter(|(_, region)| region.frame.physical_flags
+            .map_or(false, |flags| flags.contains(PhysicalPageFlags::COMPOUND_HEAD)))
+        .map(|(&v_start, _)| v_start)
+        .collect();
+
+    for head_start in head_starts {
+        let head_len = physical_map.0[&head_start].len;
+        let tail_start = head_start + head_len;
+
+        let is_tail = physical_map.0.get(&tail_start)
+            .map_or(false, |region| region.frame.physical_flags
+                .map_or(false, |flags| flags.contains(PhysicalPageFlags::COMPOUND_TAIL)));
+
+        if !is_tail {
+            continue;
+        }
+
+        let tail_region = physical_map.0.remove(&tail_start).unwrap();
+        let head_region = physical_map.0.get_mut(&head_start).unwrap();
+        head_region.len += tail_region.len;
+        head_region.page_order = compound_order(head_region.len);
+    }
+}
+
+
 impl fmt::Debug for PageFrameMap {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for (key, value) in &self.0 {
@@ -174,8 +377,8 @@ impl MemoryRegion {
         let mut physical_map: PageFrameMap = PageFrameMap(HashMap::new());
 
         // start and end page numbers
-        let page_index_start = self.v_region_start / LINUX_PAGE_SIZE;
-        let page_index_end = self.v_region_end / LINUX_PAGE_SIZE;
+        let page_index_start = self.v_region_start / page_size();
+        let page_index_end = self.v_region_end / page_size();
 
         // page start and read length in bytes (one page has a 64bit entry)
         let page_start_bytes = page_index_start * 8;
@@ -186,15 +389,24 @@ impl MemoryRegion {
 
         // read the all page indices at once
         let mut byte_buf = vec![0u8; read_length_bytes];
-        pagemap.read(&mut byte_buf)?;
+        pagemap.read_exact(&mut byte_buf)?;
 
         // convert bytes to u64
         let mut buf_rdr = io::Cursor::new(byte_buf);
         let mut u64_buf = vec![0u64; read_length_bytes / 8];
         buf_rdr.read_u64_into::<NativeEndian>(&mut u64_buf).unwrap();
 
+        // `/proc/kpageflags` requires root/CAP_SYS_ADMIN; degrade gracefully if we can't open it
+        let mut kpageflags_file = File::open("/proc/kpageflags").ok();
+
         let mut page_frames = u64_buf.into_iter()
             .map(PageFrame::new)
+            .map(|mut frame| {
+                if let PageLocation::RAM(pfn) = frame.page_location {
+                    frame.physical_flags = read_physical_flags(&mut kpageflags_file, pfn);
+                }
+                frame
+            })
             .zip(page_index_start..page_index_end);
 
         // check if the iterator is empty, and if so, terminate early
@@ -209,7 +421,7 @@ impl MemoryRegion {
         for (page_frame, v_page) in page_frames {
             // we combine sequences of identical frames or frames that follow another
             if (last_frame != page_frame) && (!last_frame.is_previous_page(&page_frame)) {
-                let frame = PageFrameRegion { frame: last_frame, len: v_page - v_start };
+                let frame = PageFrameRegion { frame: last_frame, len: v_page - v_start, page_order: 0 };
                 physical_map.0.insert(v_start,
                                       frame);
                 v_start = v_page;
@@ -220,7 +432,9 @@ impl MemoryRegion {
 
         // add the last open PageFrameRegion
         physical_map.0.insert(v_start,
-                              PageFrameRegion{ frame: last_frame, len: page_index_end - v_start});
+                              PageFrameRegion{ frame: last_frame, len: page_index_end - v_start, page_order: 0 });
+
+        coalesce_compound_pages(&mut physical_map);
 
         // if the physical address map is empty, insert None. Else insert the map.
         self.physical_regions = Some(physical_map);
@@ -267,4 +481,104 @@ impl MemoryRegion {
     pub fn has_physical_mapping(&self) -> bool {
         return self.physical_regions.is_some();
     }
+
+    pub fn physical_regions(&self) -> Option<&PageFrameMap> {
+        self.physical_regions.as_ref()
+    }
+
+    /// Sample which pages in this region have been written since the soft-dirty bits were last
+    /// cleared via `/proc/[pid]/clear_refs`.
+    ///
+    /// Read-only and file-backed-readonly regions can never accumulate writes, so they are
+    /// reported as zero rather than paying for (and risking an error from) a pagemap read.
+    pub fn sample_soft_dirty(&self, pagemap: &mut File) -> io::Result<WorkingSetSample> {
+        if !self.permissions.contains(MemoryPermissions::WRITE) {
+            return Ok(WorkingSetSample { dirtied_addresses: Vec::new() });
+        }
+
+        let page_index_start = self.v_region_start / page_size();
+        let page_index_end = self.v_region_end / page_size();
+
+        let page_start_bytes = page_index_start * 8;
+        let read_length_bytes = (page_index_end - page_index_start + 1) * 8;
+
+        pagemap.seek(io::SeekFrom::Start(page_start_bytes as u64))?;
+
+        let mut byte_buf = vec![0u8; read_length_bytes];
+        pagemap.read_exact(&mut byte_buf)?;
+
+        let mut buf_rdr = io::Cursor::new(byte_buf);
+        let mut u64_buf = vec![0u64; read_length_bytes / 8];
+        buf_rdr.read_u64_into::<NativeEndian>(&mut u64_buf).unwrap();
+
+        // the `+ 1` above reads one word past the region end; the bounded zip range drops it.
+        let dirtied_addresses = u64_buf.into_iter()
+            .zip(page_index_start..page_index_end)
+            .filter_map(|(page_entry, v_page)| {
+                if PageFrame::new(page_entry).is_soft_dirty {
+                    Some(v_page * page_size())
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Ok(WorkingSetSample { dirtied_addresses })
+    }
+
+    /// Estimate this region's working set using the kernel's idle-page tracking, which (unlike
+    /// soft-dirty) also catches reads. Returns, per `PageFrameRegion` (keyed by the same page
+    /// index used by `physical_regions`), the fraction of its frames accessed during `interval`.
+    ///
+    /// Marks every trackable resident frame idle in `idle_bitmap`, waits `interval`, then
+    /// re-resolves the region's PFNs from `pagemap` (a page can be reclaimed or migrated while
+    /// we wait) and checks whether its idle bit is now clear. Only LRU pages are tracked by the
+    /// idle bitmap, so `UNEVICTABLE`/`SLAB` frames are excluded. Requires root.
+    pub fn scan_idle_pages(&self, pagemap: &mut File, idle_bitmap: &mut File, interval: Duration)
+        -> io::Result<HashMap<usize, f64>> {
+        let physical_regions = match &self.physical_regions {
+            Some(physical_regions) => physical_regions,
+            None => return Ok(HashMap::new()),
+        };
+
+        let mut kpageflags = File::open("/proc/kpageflags").ok();
+
+        for (&v_start, page_frame_region) in physical_regions.regions() {
+            for pfn in resolve_trackable_pfns(pagemap, &mut kpageflags, v_start, page_frame_region.len())? {
+                set_idle_bit(idle_bitmap, pfn)?;
+            }
+        }
+
+        thread::sleep(interval);
+
+        let mut fractions = HashMap::new();
+        for (&v_start, page_frame_region) in physical_regions.regions() {
+            let pfns = resolve_trackable_pfns(pagemap, &mut kpageflags, v_start, page_frame_region.len())?;
+            if pfns.is_empty() {
+                continue;
+            }
+
+            let accessed = pfns.iter().filter(|&&pfn| !is_idle(idle_bitmap, pfn).unwrap_or(true)).count();
+            fractions.insert(v_start, accessed as f64 / pfns.len() as f64);
+        }
+
+        Ok(fractions)
+    }
+}
+
+
+/// The pages of a single `MemoryRegion` that were written during a soft-dirty sampling window.
+#[derive(Debug)]
+pub struct WorkingSetSample {
+    dirtied_addresses: Vec<usize>,
+}
+
+impl WorkingSetSample {
+    pub fn dirtied_pages(&self) -> usize {
+        self.dirtied_addresses.len()
+    }
+
+    pub fn dirtied_addresses(&self) -> &[usize] {
+        &self.dirtied_addresses
+    }
 }
@@ -2,8 +2,11 @@ pub mod memory;
 
 use std::fmt;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use self::memory::MemoryRegion;
+use std::thread;
+use std::time::Duration;
+use self::memory::{MemoryRegion, WorkingSetSample};
 
 /// Enum to express the process states from `/proc/[pid]/stat`
 #[derive(Debug)]
@@ -112,6 +115,10 @@ impl ProcessMemoryMap {
 
         ProcessMemoryMap { regions: mem }
     }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
 }
 
 
@@ -147,6 +154,39 @@ impl ProcessInformation {
         self.memory.get_or_insert(ProcessMemoryMap::new_memory_map(self.pid, true))
     }
 
+    /// The process's memory map, if one has already been loaded (see `new_from_stat`'s
+    /// `preload_mapping` flag). Unlike `memory`, this never loads the map lazily, so callers
+    /// that only need read access don't have to take `&mut self`.
+    pub fn memory_map(&self) -> Option<&ProcessMemoryMap> {
+        self.memory.as_ref()
+    }
+
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    /// Sample which pages this process wrote to during `interval`, per `MemoryRegion`.
+    ///
+    /// Clears the soft-dirty bit on every page via `/proc/[pid]/clear_refs`, waits for
+    /// `interval` to let the process run, then re-reads the pagemap and collects the pages
+    /// whose soft-dirty bit is now set. The process is re-scanned after clearing, since its
+    /// memory map may have changed while we were waiting.
+    pub fn sample_working_set(&mut self, interval: Duration) -> io::Result<Vec<WorkingSetSample>> {
+        let clear_refs_path = format!("/proc/{}/clear_refs", self.pid);
+        File::create(&clear_refs_path)?.write_all(b"4\n")?;
+
+        thread::sleep(interval);
+
+        self.memory = Some(ProcessMemoryMap::new_memory_map(self.pid, true));
+
+        let pagemap_path = format!("/proc/{}/pagemap", self.pid);
+        let mut pagemap = File::open(&pagemap_path)?;
+
+        self.memory.as_ref().unwrap().regions.iter()
+            .map(|region| region.sample_soft_dirty(&mut pagemap))
+            .collect()
+    }
+
     pub fn has_physical_map(&self) -> bool {
         // if no memory mapping has been computed yet, return false
         if self.memory.is_none() {
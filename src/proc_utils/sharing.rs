@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use super::process::ProcessInformation;
+use super::process::memory::PhysicalPageFlags;
+
+/// Which processes (and which virtual region within each) reference a physical frame.
+#[derive(Debug)]
+pub struct SharedFrame {
+    map_count: usize,
+    flags: Option<PhysicalPageFlags>,
+    mappings: Vec<(usize, usize)>,
+}
+
+impl SharedFrame {
+    /// Number of page-table entries mapping this frame system-wide, per `/proc/kpagecount`.
+    pub fn map_count(&self) -> usize {
+        self.map_count
+    }
+
+    /// The physical page flags from `/proc/kpageflags`, if they were read (root only).
+    pub fn flags(&self) -> Option<PhysicalPageFlags> {
+        self.flags
+    }
+
+    /// The `(pid, virtual start page index of the PageFrameRegion)` pairs that reference this
+    /// frame.
+    pub fn mappings(&self) -> &[(usize, usize)] {
+        &self.mappings
+    }
+}
+
+/// A reverse index from physical frame number to the processes that map it.
+///
+/// Built from the `PageFrameMap`s of a set of already-scanned `ProcessInformation`s, so a
+/// caller can answer "which processes share this physical page" and quantify how much memory
+/// sharing (shared libraries, shared mappings, or KSM deduplication) is saving system-wide.
+///
+/// Each `PageFrameRegion` contributes only the PFN of its first frame; the other frames of a
+/// multi-page region are not indexed. Processes whose memory map has not been loaded are skipped.
+pub struct PhysicalSharingMap {
+    frames: HashMap<usize, SharedFrame>,
+}
+
+impl PhysicalSharingMap {
+    /// Build the reverse index. Requires root/CAP_SYS_ADMIN to read `/proc/kpagecount`.
+    pub fn build(processes: &[ProcessInformation]) -> io::Result<Self> {
+        let mut kpagecount = File::open("/proc/kpagecount")?;
+        let mut frames: HashMap<usize, SharedFrame> = HashMap::new();
+
+        for process in processes {
+            let pid = process.pid();
+
+            let memory = match process.memory_map() {
+                Some(memory) => memory,
+                None => continue,
+            };
+
+            for region in memory.regions() {
+                let physical_regions = match region.physical_regions() {
+                    Some(physical_regions) => physical_regions,
+                    None => continue,
+                };
+
+                for (&v_start, page_frame_region) in physical_regions.regions() {
+                    let pfn = match page_frame_region.frame().pfn() {
+                        Some(pfn) => pfn,
+                        None => continue,
+                    };
+
+                    let entry = frames.entry(pfn).or_insert_with(|| SharedFrame {
+                        map_count: read_map_count(&mut kpagecount, pfn).unwrap_or(0),
+                        flags: page_frame_region.frame().physical_flags(),
+                        mappings: Vec::new(),
+                    });
+                    entry.mappings.push((pid, v_start));
+                }
+            }
+        }
+
+        Ok(PhysicalSharingMap { frames })
+    }
+
+    /// Frames whose system-wide map count (from `/proc/kpagecount`) is greater than one.
+    pub fn shared_frames(&self) -> impl Iterator<Item=(&usize, &SharedFrame)> {
+        self.frames.iter().filter(|(_, shared)| shared.map_count > 1)
+    }
+
+    /// How many of the indexed frames that `pid` maps are private to it (map count <= 1).
+    /// Only the first frame of each `PageFrameRegion` is indexed, so this counts regions'
+    /// start frames, not every resident frame of the process.
+    pub fn private_frame_count(&self, pid: usize) -> usize {
+        self.frames.values()
+            .filter(|shared| shared.map_count <= 1 && shared.mappings.iter().any(|&(p, _)| p == pid))
+            .count()
+    }
+}
+
+/// Read the native-endian `u64` map count for `pfn` out of `/proc/kpagecount`.
+fn read_map_count(kpagecount: &mut File, pfn: usize) -> io::Result<usize> {
+    kpagecount.seek(io::SeekFrom::Start((pfn * 8) as u64))?;
+
+    let mut buf = [0u8; 8];
+    kpagecount.read_exact(&mut buf)?;
+
+    Ok(u64::from_ne_bytes(buf) as usize)
+}